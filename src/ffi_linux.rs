@@ -0,0 +1,53 @@
+//! Linux-specific advisory record locking via `fcntl(F_SETLK)`.
+//!
+//! The `flock` struct layout and the `F_*` command/type constants differ
+//! between Linux and the BSD-derived platforms, which is why this lives behind
+//! a per-target module.
+
+use libc::{c_int, c_short, off_t, pid_t};
+
+#[repr(C)]
+struct flock {
+    l_type: c_short,
+    l_whence: c_short,
+    l_start: off_t,
+    l_len: off_t,
+    l_pid: pid_t,
+}
+
+static F_SETLK: c_int = 6;
+static F_RDLCK: c_short = 0;
+static F_WRLCK: c_short = 1;
+static F_UNLCK: c_short = 2;
+static SEEK_SET: c_short = 0;
+
+extern {
+    // Declared non-variadic with the single pointer argument F_SETLK expects;
+    // this is the conventional way to reach `fcntl`'s locking commands from
+    // Rust without a variadic call.
+    fn fcntl(fd: c_int, cmd: c_int, arg: *mut flock) -> c_int;
+}
+
+/// Acquires a whole-file lock on `fd`; `exclusive` selects a write lock,
+/// otherwise a shared read lock. Returns 0 on success, -1 on failure (with
+/// `errno` set, e.g. `EAGAIN`/`EACCES` for a conflict).
+pub fn lock(fd: c_int, exclusive: bool) -> c_int {
+    set(fd, if exclusive { F_WRLCK } else { F_RDLCK })
+}
+
+/// Releases any lock held on `fd`.
+pub fn unlock(fd: c_int) -> c_int {
+    set(fd, F_UNLCK)
+}
+
+fn set(fd: c_int, l_type: c_short) -> c_int {
+    let mut fl = flock {
+        l_type: l_type,
+        l_whence: SEEK_SET,
+        l_start: 0,
+        l_len: 0,
+        l_pid: 0,
+    };
+
+    unsafe { fcntl(fd, F_SETLK, &mut fl) }
+}
@@ -0,0 +1,258 @@
+//! POSIX-backed pidfile handle.
+//!
+//! Works directly against a raw file descriptor rather than `std::io::File` so
+//! that the same descriptor used for I/O can be passed to the advisory locking
+//! primitives in `ffi`.
+
+use std::io::{IoResult, IoError, ResourceUnavailable, PermissionDenied};
+use std::c_str::ToCStr;
+use libc;
+use libc::{c_int, c_void, c_uint, pid_t, off_t, size_t};
+
+use ffi;
+use {LockMode, Exclusive, Shared};
+
+static O_RDONLY: c_int = 0;
+static O_RDWR: c_int = 2;
+static O_CREAT: c_int = 0o100;
+static SEEK_SET: c_int = 0;
+static READ_CHUNK: uint = 512;
+
+pub struct File {
+    fd: c_int,
+    closed: bool,
+}
+
+impl File {
+    pub fn open(path: &Path, create: bool, write: bool, perm: u32) -> IoResult<File> {
+        let mut flags = if write { O_RDWR } else { O_RDONLY };
+        if create {
+            flags |= O_CREAT;
+        }
+
+        let fd = path.to_c_str().with_ref(|p| unsafe {
+            libc::open(p, flags, perm as c_uint)
+        });
+
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+
+        Ok(File { fd: fd, closed: false })
+    }
+
+    /// Takes a whole-file advisory lock. `Exclusive` requests a write lock that
+    /// only one holder may own; `Shared` requests a read lock that any number
+    /// of readers may hold while no exclusive lock is present. Returns `false`
+    /// when the lock is held by someone else.
+    pub fn lock(&mut self, mode: LockMode) -> IoResult<bool> {
+        let exclusive = match mode {
+            Exclusive => true,
+            Shared => false,
+        };
+
+        if ffi::lock(self.fd, exclusive) == 0 {
+            return Ok(true);
+        }
+
+        let err = IoError::last_error();
+
+        // A held lock shows up as EAGAIN/EACCES, which map to
+        // `ResourceUnavailable`/`PermissionDenied`; anything else is a real
+        // error.
+        match err.kind {
+            ResourceUnavailable | PermissionDenied => Ok(false),
+            _ => Err(err),
+        }
+    }
+
+    /// Releases the advisory lock held on this descriptor.
+    pub fn unlock(&mut self) -> IoResult<()> {
+        if ffi::unlock(self.fd) == 0 {
+            Ok(())
+        } else {
+            Err(IoError::last_error())
+        }
+    }
+
+    pub fn truncate(&mut self) -> IoResult<()> {
+        if unsafe { libc::ftruncate(self.fd, 0) } < 0 {
+            return Err(IoError::last_error());
+        }
+
+        self.seek(0)
+    }
+
+    /// Writes a host-qualified `hostname:pid` record so the owner can be
+    /// disambiguated when the pidfile directory is shared across machines.
+    pub fn write(&mut self, pid: pid_t) -> IoResult<()> {
+        let record = format!("{}:{}\n", hostname(), pid);
+        self.write_all(record.as_bytes())
+    }
+
+    /// Reads the owner recorded in the file as `(hostname, pid)`. A legacy
+    /// bare-integer file is parsed with an empty hostname; an empty file yields
+    /// `(String::new(), 0)`.
+    pub fn check(&mut self) -> IoResult<(String, pid_t)> {
+        let contents = try!(self.read_all());
+
+        if contents.len() == 0 {
+            return Ok((String::new(), 0));
+        }
+
+        let text = String::from_utf8_lossy(contents.as_slice());
+        let line = text.as_slice().lines().next().unwrap_or("");
+
+        Ok(parse_record(line))
+    }
+
+    /// Replaces the payload stored beneath the pid header with `data`,
+    /// truncating any previously stored payload while leaving the header
+    /// intact.
+    pub fn write_data(&mut self, data: &[u8]) -> IoResult<()> {
+        let off = try!(self.header_len());
+        try!(self.seek(off));
+        try!(self.write_all(data));
+
+        if unsafe { libc::ftruncate(self.fd, off + data.len() as off_t) } < 0 {
+            return Err(IoError::last_error());
+        }
+
+        Ok(())
+    }
+
+    /// Reads back the payload stored beneath the pid header.
+    pub fn read_data(&mut self) -> IoResult<Vec<u8>> {
+        let off = try!(self.header_len());
+        try!(self.seek(off));
+        self.read_to_end()
+    }
+
+    /// The byte offset just past the pid header (the first newline), i.e. where
+    /// the caller payload begins.
+    fn header_len(&self) -> IoResult<off_t> {
+        let contents = try!(self.read_all());
+        let mut len = 0u;
+
+        for &b in contents.iter() {
+            len += 1;
+            if b == ('\n' as u8) {
+                break;
+            }
+        }
+
+        Ok(len as off_t)
+    }
+
+    /// Closes the descriptor. Idempotent: a second call (or the `Drop` impl
+    /// afterwards) is a no-op.
+    pub fn close(&mut self) -> IoResult<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.closed = true;
+
+        if unsafe { libc::close(self.fd) } < 0 {
+            return Err(IoError::last_error());
+        }
+
+        Ok(())
+    }
+
+    fn seek(&self, off: off_t) -> IoResult<()> {
+        if unsafe { libc::lseek(self.fd, off, SEEK_SET) } < 0 {
+            return Err(IoError::last_error());
+        }
+
+        Ok(())
+    }
+
+    fn write_all(&self, buf: &[u8]) -> IoResult<()> {
+        let mut written = 0u;
+
+        while written < buf.len() {
+            let rest = buf.slice_from(written);
+            let n = unsafe {
+                libc::write(self.fd, rest.as_ptr() as *const c_void, rest.len() as size_t)
+            };
+
+            if n < 0 {
+                return Err(IoError::last_error());
+            }
+
+            written += n as uint;
+        }
+
+        Ok(())
+    }
+
+    fn read_all(&self) -> IoResult<Vec<u8>> {
+        try!(self.seek(0));
+        self.read_to_end()
+    }
+
+    fn read_to_end(&self) -> IoResult<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut buf = [0u8, ..READ_CHUNK];
+
+        loop {
+            let n = unsafe {
+                libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t)
+            };
+
+            if n < 0 {
+                return Err(IoError::last_error());
+            }
+
+            if n == 0 {
+                break;
+            }
+
+            out.push_all(buf.slice_to(n as uint));
+        }
+
+        Ok(out)
+    }
+}
+
+impl Drop for File {
+    #[allow(unused_must_use)]
+    fn drop(&mut self) {
+        if !self.closed {
+            unsafe { libc::close(self.fd); }
+        }
+    }
+}
+
+/// Parses one record line into `(hostname, pid)`. A `hostname:pid` line splits
+/// on the final colon; a bare integer is treated as a legacy, host-agnostic
+/// record with an empty hostname.
+fn parse_record(line: &str) -> (String, pid_t) {
+    match line.rfind(':') {
+        Some(idx) => {
+            let host = line.slice_to(idx).to_string();
+            let pid = from_str::<pid_t>(line.slice_from(idx + 1).trim()).unwrap_or(0);
+            (host, pid)
+        },
+        None => (String::new(), from_str::<pid_t>(line.trim()).unwrap_or(0))
+    }
+}
+
+/// The local machine's hostname as reported by `gethostname`, or an empty
+/// string if it could not be determined.
+pub fn hostname() -> String {
+    let mut buf = Vec::from_elem(256u, 0u8);
+
+    let ret = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() as size_t)
+    };
+
+    if ret != 0 {
+        return String::new();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+    String::from_utf8_lossy(buf.as_slice()).to_string()
+}
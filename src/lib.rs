@@ -7,8 +7,10 @@ extern crate libc;
 #[phase(plugin, link)]
 extern crate log;
 
-use std::io::{FilePermission, IoResult, IoError, FileNotFound};
+use std::io::{FilePermission, IoResult, IoError, FileNotFound, InvalidInput};
 use std::io::fs;
+use std::io::timer;
+use std::time::Duration;
 use std::path::{BytesContainer, Path};
 use libc::pid_t;
 use file::File;
@@ -30,35 +32,144 @@ pub fn at<B: BytesContainer>(path: B) -> Request {
         pid: pid(),
         path: Path::new(path),
         perm: FilePermission::from_bits(0o644)
-            .expect("0o644 is not a valid file permission")
+            .expect("0o644 is not a valid file permission"),
+        mode: Exclusive
     }
 }
 
+/// The kind of lock to acquire: an `Exclusive` (write) lock that at most one
+/// holder may own, or a `Shared` (read) lock that any number of readers may
+/// hold concurrently while no exclusive lock is held.
+#[deriving(Show, PartialEq, Eq, Clone)]
+pub enum LockMode {
+    Exclusive,
+    Shared,
+}
+
 pub struct Request {
     pid: pid_t,
     path: Path,
-    perm: FilePermission
+    perm: FilePermission,
+    mode: LockMode
 }
 
 impl Request {
+    /// Selects whether `lock` acquires an exclusive or a shared lock. Defaults
+    /// to `Exclusive`.
+    pub fn mode(mut self, mode: LockMode) -> Request {
+        self.mode = mode;
+        self
+    }
+
     pub fn lock(self) -> LockResult<Lock> {
-        let res = File::open(&self.path, true, true, self.perm.bits());
-        let mut f = try!(res.map_err(LockError::io_error));
+        // A conflicting file may have been left behind by a process that has
+        // since died. Probe the recorded owner and, if it is gone, reclaim the
+        // orphaned file and try again rather than failing outright.
+        let mut attempts = 0u;
+
+        let exclusive = self.mode == Exclusive;
+
+        loop {
+            // A shared (reader) lock only coordinates access: it must not
+            // create or rewrite the record, so it opens the existing file
+            // read-only and leaves the pid payload untouched.
+            let res = File::open(&self.path, exclusive, exclusive, self.perm.bits());
+            let mut f = try!(res.map_err(LockError::io_error));
+
+            if try!(f.lock(self.mode).map_err(LockError::io_error)) {
+                // An exclusive holder owns the record and (re)writes its own
+                // pid; a shared holder only coordinates, so the meaningful owner
+                // is whoever the writer recorded in the file.
+                let pidfile = if exclusive {
+                    try!(f.truncate().map_err(LockError::io_error));
+                    try!(f.write(self.pid).map_err(LockError::io_error));
+                    Pidfile { pid: self.pid as uint, hostname: file::hostname() }
+                } else {
+                    let (host, pid) = try!(f.check().map_err(LockError::io_error));
+                    Pidfile { pid: pid as uint, hostname: host }
+                };
+
+                debug!("lock acquired");
+
+                return Ok(Lock {
+                    pidfile: pidfile,
+                    handle: f,
+                    path: self.path,
+                    state: Acquired,
+                    mode: self.mode
+                })
+            }
 
-        if !try!(f.lock().map_err(LockError::io_error)) {
-            return Err(LockError::conflict());
+            let (host, pid) = try!(f.check().map_err(LockError::io_error));
+            let owner = Pidfile { pid: pid as uint, hostname: host };
+
+            // The lock was held against us, which for an `fcntl` lock already
+            // implies a live owner (the kernel drops the lock when its owner
+            // dies). An empty or partially-written record (`pid == 0`) is a
+            // writer mid-`truncate`/`write`, not a stale file — treat it as a
+            // conflict and never unlink it.
+            if pid == 0 || owner.is_running() {
+                return Err(LockError::conflict());
+            }
+
+            // A shared reader never owns the record and must never unlink it;
+            // reclaiming a stale file is an exclusive writer's job only.
+            if !exclusive {
+                return Err(LockError::conflict());
+            }
+
+            attempts += 1;
+
+            if attempts > MAX_RECLAIM_ATTEMPTS {
+                return Err(LockError::conflict());
+            }
+
+            debug!("reclaiming stale pidfile; pid={}", pid);
+            try!(fs::unlink(&self.path).map_err(LockError::io_error));
         }
+    }
 
-        try!(f.truncate().map_err(LockError::io_error));
-        try!(f.write(self.pid).map_err(LockError::io_error));
+    /// Acquires the lock, runs `f` while holding it, then unconditionally
+    /// releases the lock (unlinking the file for an exclusive lock) and
+    /// forwards `f`'s return value. Acquisition is attempted up to
+    /// `MAX_LOCK_ATTEMPTS` times so that a conflict against a file whose owner
+    /// has since died or vanished is not reported as a hard failure. Should `f`
+    /// panic, the lock's `Drop` impl still unlinks an exclusive lock's file.
+    pub fn with_lock<R>(self, f: |&Pidfile| -> R) -> LockResult<R> {
+        let mut attempts = 0u;
 
-        debug!("lock acquired");
+        loop {
+            attempts += 1;
 
-        return Ok(Lock {
-            pidfile: Pidfile { pid: self.pid as uint },
-            handle: f,
-            path: self.path
-        })
+            let req = Request {
+                pid: self.pid,
+                path: self.path.clone(),
+                perm: self.perm,
+                mode: self.mode
+            };
+
+            match req.lock() {
+                Ok(mut lock) => {
+                    let result = f(&lock.pidfile());
+                    try!(lock.release().map_err(LockError::io_error));
+                    return Ok(result);
+                },
+                Err(e) => {
+                    if e.conflict && attempts < MAX_LOCK_ATTEMPTS {
+                        // An exclusive `lock` already reclaims files whose owner
+                        // is dead, so a conflict here usually means a live
+                        // owner (a shared request never reclaims). Back off before
+                        // retrying rather than busy-spinning in case it is
+                        // releasing the lock right now.
+                        debug!("lock conflict; retrying (attempt {})", attempts);
+                        timer::sleep(Duration::milliseconds(RETRY_BACKOFF_MS * attempts as i64));
+                        continue;
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
     }
 
     pub fn check(self) -> IoResult<Option<Pidfile>> {
@@ -79,16 +190,23 @@ impl Request {
             }
         };
 
-        let pid = try!(f.check());
+        let (host, pid) = try!(f.check());
 
         if pid == 0 {
             debug!("no lock acquired -- file exists");
             return Ok(None);
         }
 
+        let pidfile = Pidfile { pid: pid as uint, hostname: host };
+
+        if !pidfile.is_running() {
+            debug!("no lock acquired -- owner pid={} is gone", pid);
+            return Ok(None);
+        }
+
         debug!("lock acquired; pid={}", pid);
 
-        Ok(Some(Pidfile { pid: pid as uint }))
+        Ok(Some(pidfile))
     }
 }
 
@@ -96,26 +214,136 @@ impl Request {
 /// active lock.
 #[deriving(Clone)]
 pub struct Pidfile {
-    pid: uint
+    pid: uint,
+    hostname: String
 }
 
 impl Pidfile {
     pub fn pid(&self) -> uint {
         self.pid
     }
+
+    /// The hostname recorded alongside the pid. On a shared filesystem this
+    /// distinguishes an owner running on this machine from one elsewhere.
+    pub fn hostname(&self) -> String {
+        self.hostname.clone()
+    }
+
+    /// Returns whether the process that owns this pidfile is still alive. A pid
+    /// owned by a *different, named* host cannot be signalled meaningfully, so
+    /// it is conservatively treated as running. A legacy file with no recorded
+    /// hostname is assumed local and falls through to the liveness probe, so
+    /// stale reclamation still applies to it.
+    pub fn is_running(&self) -> bool {
+        if self.hostname.len() > 0 && self.hostname != file::hostname() {
+            return true;
+        }
+
+        process_status(self.pid as pid_t) == Running
+    }
+}
+
+/// Liveness of the process recorded in a pidfile, as reported by a null
+/// signal (`kill(pid, 0)`).
+#[deriving(Show, PartialEq, Eq)]
+pub enum ProcessStatus {
+    /// The process is alive (the signal was delivered, or `EPERM` told us it
+    /// exists but belongs to another user).
+    Running,
+    /// No such process (`ESRCH`); the pidfile is stale.
+    Dead,
+    /// The liveness could not be determined.
+    Unknown,
+}
+
+/// The point a `Lock` has reached in its lifecycle.
+#[deriving(Show, PartialEq, Eq)]
+enum LockState {
+    /// Holding the lock; the file exists (and, for an exclusive lock, is owned
+    /// by us).
+    Acquired,
+    /// Explicitly released; the handle has been unlocked and closed (and, for
+    /// an exclusive lock, the file unlinked).
+    Released,
 }
 
 pub struct Lock {
     pidfile: Pidfile,
     path: Path,
-
-    #[allow(dead_code)]
+    state: LockState,
+    mode: LockMode,
     handle: File,
 }
 
 impl Lock {
     pub fn pidfile(&self) -> Pidfile {
-        self.pidfile
+        self.pidfile.clone()
+    }
+
+    /// The mode under which this lock is held.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Writes `data` to the file beneath the pid header, truncating any
+    /// previously stored payload. Useful for stashing a small amount of status
+    /// (start time, command line, a heartbeat timestamp) while holding the
+    /// lock.
+    pub fn write_data(&mut self, data: &[u8]) -> IoResult<()> {
+        if self.state != Acquired {
+            return Err(invalid_state("lock is not held"));
+        }
+
+        // A shared lock opens the file read-only, so writing would fail deep in
+        // libc with a confusing `EBADF`; reject it up front instead.
+        if self.mode != Exclusive {
+            return Err(invalid_state("cannot write under a shared lock"));
+        }
+
+        self.handle.write_data(data)
+    }
+
+    /// Reads back the payload stored beneath the pid header.
+    pub fn read_data(&mut self) -> IoResult<Vec<u8>> {
+        if self.state != Acquired {
+            return Err(invalid_state("lock is not held"));
+        }
+
+        self.handle.read_data()
+    }
+
+    /// Releases the lock early: unlocks and closes the handle, unlinks the
+    /// pidfile (exclusive holders only) and reports any failure. Advancing the
+    /// lifecycle to `Released`
+    /// makes a second `release` (or a later `read_data`/`write_data`) fail with
+    /// a clear `InvalidState` error and turns the `Drop` impl into a no-op.
+    /// Every cleanup step is attempted even if an earlier one fails, so an
+    /// exclusive holder's pidfile is always unlinked; the first error
+    /// encountered is returned.
+    ///
+    /// Note: this deliberately takes `&mut self` rather than consuming `self`.
+    /// A consuming signature would enforce single-release through move
+    /// semantics at compile time, but then the runtime double-release guard
+    /// below could never fire; keeping `&mut self` is what makes that
+    /// `InvalidState` check reachable.
+    pub fn release(&mut self) -> IoResult<()> {
+        if self.state == Released {
+            return Err(invalid_state("lock has already been released"));
+        }
+
+        self.state = Released;
+
+        let unlocked = self.handle.unlock();
+        let closed = self.handle.close();
+        // Only an exclusive holder owns the pidfile; a shared reader must not
+        // unlink it out from under the writer and the other readers.
+        let unlinked = if self.mode == Exclusive {
+            fs::unlink(&self.path)
+        } else {
+            Ok(())
+        };
+
+        unlocked.and(closed).and(unlinked)
     }
 }
 
@@ -123,8 +351,16 @@ impl Drop for Lock {
     #[allow(unused_must_use)]
     fn drop(&mut self) {
         // Some non-critical cleanup. We do not assume that the pidfile will
-        // properly get cleaned up since this handler may not get executed.
-        fs::unlink(&self.path);
+        // properly get cleaned up since this handler may not get executed. If
+        // the lock was released explicitly there is nothing left to do.
+        if self.state == Released {
+            return;
+        }
+
+        // A shared reader never owns the pidfile, so it must not unlink it.
+        if self.mode == Exclusive {
+            fs::unlink(&self.path);
+        }
     }
 }
 
@@ -155,3 +391,40 @@ pub type LockResult<T> = Result<T, LockError>;
 fn pid() -> pid_t {
     unsafe { libc::getpid() }
 }
+
+/// The maximum number of times `lock` will reclaim a stale file and retry
+/// before giving up and reporting a conflict.
+static MAX_RECLAIM_ATTEMPTS: uint = 5;
+
+/// The maximum number of acquisition attempts made by `with_lock` before a
+/// persistent conflict is reported to the caller.
+static MAX_LOCK_ATTEMPTS: uint = 5;
+
+/// Base backoff, in milliseconds, between `with_lock` retries; scaled linearly
+/// by the attempt number so repeated conflicts wait progressively longer.
+static RETRY_BACKOFF_MS: i64 = 10;
+
+/// Builds the error returned when a `Lock` is used from a state that does not
+/// permit the requested operation (e.g. releasing twice).
+fn invalid_state(detail: &str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "invalid lock state",
+        detail: Some(detail.to_string()),
+    }
+}
+
+/// Probes whether `pid` names a live process using a null signal.
+fn process_status(pid: pid_t) -> ProcessStatus {
+    use libc::consts::os::posix88::{EPERM, ESRCH};
+
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return Running;
+    }
+
+    match std::os::errno() as i32 {
+        EPERM => Running,
+        ESRCH => Dead,
+        _ => Unknown,
+    }
+}